@@ -8,15 +8,24 @@ use matrix_sdk::{
     Client as MatrixClient,
     room::{Room as MatrixRoom, MessagesOptions},
     config::ClientConfig,
+    attachment::AttachmentConfig,
+    encryption::verification::Sas,
     LoopCtrl,
     Session,
-    media::{MediaRequest, MediaFormat, MediaType},
+    media::{MediaRequest, MediaFormat, MediaType, MediaThumbnailSize},
 };
 pub use matrix_sdk::{
     ruma::{
-        api::client::r0::account::register,
-        UserId, RoomId, MxcUri, DeviceId, ServerName,
-        events::{AnyRoomEvent, AnyMessageEvent}
+        api::client::r0::{
+            account::register,
+            uiaa::{AuthData, Dummy},
+            media::Method,
+        },
+        events::{
+            AnyRoomEvent, AnyMessageEvent, AnyToDeviceEvent,
+            room::message::RoomMessageEventContent,
+        },
+        UserId, RoomId, MxcUri, DeviceId, ServerName, UInt,
     }
 };
 use lazy_static::lazy_static;
@@ -25,10 +34,15 @@ use url::Url;
 use serde_json;
 use parking_lot::RwLock;
 use derive_builder::Builder;
+use mime::Mime;
 use std::sync::Arc;
 
 use serde::{Serialize, Deserialize};
 
+mod messages;
+pub use messages::Message;
+use messages::message_from_content;
+
 // use ruma::events::{AnyRoomEvent, AnyMessageEvent};
 
 lazy_static! {
@@ -64,13 +78,23 @@ pub struct ClientState {
 pub struct Client {
     client: MatrixClient,
     state: Arc<RwLock<ClientState>>,
+    verification_delegate: Arc<RwLock<Option<Arc<dyn VerificationDelegate>>>>,
+    sas: Arc<RwLock<Option<Sas>>>,
+}
+
+/// A `Session` plus the homeserver it belongs to, so a restore token is
+/// self-contained and doesn't need a side channel to know where to log
+/// back in.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FfiSession {
+    pub homeserver: String,
+    pub session: Session,
 }
 
 #[derive(Serialize, Deserialize)]
 struct RestoreToken {
     is_guest: bool,
-    homeurl: String,
-    session: Session,
+    session: FfiSession,
 }
 
 pub struct Room {
@@ -83,7 +107,13 @@ pub enum ClientError {
     #[error("client error: {msg}")]
     Generic {
         msg: String,
-    }
+    },
+
+    #[error("homeserver requires unsupported auth stages: {stages:?}")]
+    UnsupportedAuthStage {
+        stages: Vec<String>,
+        session: String,
+    },
 }
 
 impl From<anyhow::Error> for ClientError {
@@ -92,6 +122,12 @@ impl From<anyhow::Error> for ClientError {
     }
 }
 
+impl From<matrix_sdk::Error> for ClientError {
+    fn from(e: matrix_sdk::Error) -> ClientError {
+        ClientError::Generic { msg: e.to_string() }
+    }
+}
+
 impl Room {
     pub fn identifier(&self) -> String {
         return self.room.room_id().to_string()
@@ -139,14 +175,16 @@ impl Room {
         return self.room.is_space()
     }
 
-    pub fn messages(&self) -> Result<Vec<String>> {
+    pub fn messages(&self) -> Result<Vec<Arc<Message>>> {
         let r = self.room.clone();
         RUNTIME.block_on(async move {
 
             let stream = r.messages(MessagesOptions::forward("")).await.expect("No messages");
             let messages = stream.chunk.iter().filter_map(|e|
                 match e.event.deserialize() {
-                    Ok(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(m))) => Some(format!("{}: {:?}", m.sender, m.content)),
+                    Ok(AnyRoomEvent::Message(AnyMessageEvent::RoomMessage(m))) => {
+                        message_from_content(m.sender.to_string(), m.event_id.to_string(), m.origin_server_ts, m.content)
+                    }
                     Ok(e) => { println!("Skipping event {:?}", e); None},
                     Err(e) => { println!("Error parsing event: {:?}", e); None },
                 }
@@ -155,6 +193,39 @@ impl Room {
             Ok(messages)
         })
     }
+
+    pub fn send_text(&self, body: String) -> Result<String> {
+        let r = self.room.clone();
+        RUNTIME.block_on(async move {
+            let content = RoomMessageEventContent::text_plain(body);
+            let response = r.send(content, None).await?;
+            Ok(response.event_id.to_string())
+        })
+    }
+
+    pub fn send_formatted(&self, body: String, html: String) -> Result<String> {
+        let r = self.room.clone();
+        RUNTIME.block_on(async move {
+            let content = RoomMessageEventContent::text_html(body, html);
+            let response = r.send(content, None).await?;
+            Ok(response.event_id.to_string())
+        })
+    }
+
+    pub fn send_attachment(&self, body: String, mime: String, data: Vec<u8>) -> Result<String> {
+        let r = self.room.clone();
+        RUNTIME.block_on(async move {
+            let content_type: Mime = mime.parse()?;
+
+            #[cfg(feature = "image-proc")]
+            let config = AttachmentConfig::new().generate_thumbnail(None);
+            #[cfg(not(feature = "image-proc"))]
+            let config = AttachmentConfig::new();
+
+            let response = r.send_attachment(&body, &content_type, data, config).await?;
+            Ok(response.event_id.to_string())
+        })
+    }
 }
 
 impl std::ops::Deref for Room {
@@ -172,24 +243,125 @@ impl std::ops::Deref for Client {
     }
 }
 
+/// Clone the delegate out from behind its read lock and release the lock
+/// before returning, so callers never hold it while the delegate callback
+/// runs (the callback may re-enter through `set_verification_delegate`,
+/// which takes the write lock on the same `RwLock`).
+fn cloned_verification_delegate(
+    delegate: &Arc<RwLock<Option<Arc<dyn VerificationDelegate>>>>,
+) -> Option<Arc<dyn VerificationDelegate>> {
+    delegate.read().clone()
+}
+
+/// Inspect one `m.key.verification.*` to-device event and, if it belongs
+/// to a SAS flow, update the in-flight `Sas` and notify the delegate.
+async fn handle_verification_event(
+    client: &MatrixClient,
+    delegate: &Arc<RwLock<Option<Arc<dyn VerificationDelegate>>>>,
+    sas_slot: &Arc<RwLock<Option<Sas>>>,
+    event: &matrix_sdk::ruma::serde::Raw<AnyToDeviceEvent>,
+) {
+    let event = match event.deserialize() {
+        Ok(event) => event,
+        Err(e) => { println!("Error parsing to-device event: {:?}", e); return },
+    };
+
+    match event {
+        // No `Sas` exists yet at the request stage (that's only created
+        // once the peer's `m.key.verification.start` arrives below); this
+        // arm only announces that a verification was asked for.
+        AnyToDeviceEvent::KeyVerificationRequest(_e) => {
+            if let Some(delegate) = cloned_verification_delegate(delegate) {
+                delegate.verification_requested();
+            }
+        }
+        AnyToDeviceEvent::KeyVerificationStart(e) => {
+            if let Some(sas) = client
+                .get_verification(&e.sender, e.content.transaction_id.as_str())
+                .await
+                .and_then(|v| v.sas())
+            {
+                *sas_slot.write() = Some(sas);
+            }
+            if let Some(delegate) = cloned_verification_delegate(delegate) {
+                delegate.verification_requested();
+            }
+        }
+        AnyToDeviceEvent::KeyVerificationKey(e) => {
+            let sas = client
+                .get_verification(&e.sender, e.content.transaction_id.as_str())
+                .await
+                .and_then(|v| v.sas());
+
+            if let Some(sas) = sas {
+                if let (Some(emoji), Some(decimals)) = (sas.emoji(), sas.decimals()) {
+                    let emoji = emoji.iter().map(|e| e.symbol.to_owned()).collect();
+                    let decimals = vec![decimals.0, decimals.1, decimals.2];
+                    *sas_slot.write() = Some(sas);
+                    if let Some(delegate) = cloned_verification_delegate(delegate) {
+                        delegate.sas_ready(emoji, decimals);
+                    }
+                }
+            }
+        }
+        AnyToDeviceEvent::KeyVerificationMac(e) => {
+            let sas = client
+                .get_verification(&e.sender, e.content.transaction_id.as_str())
+                .await
+                .and_then(|v| v.sas());
+
+            if let Some(sas) = sas {
+                if sas.is_done() {
+                    if let Some(delegate) = cloned_verification_delegate(delegate) {
+                        delegate.verification_done();
+                    }
+                    *sas_slot.write() = None;
+                }
+            }
+        }
+        AnyToDeviceEvent::KeyVerificationCancel(_) => {
+            *sas_slot.write() = None;
+            if let Some(delegate) = cloned_verification_delegate(delegate) {
+                delegate.verification_cancelled();
+            }
+        }
+        _ => {}
+    }
+}
+
 pub trait ClientDelegate: Sync + Send {
     fn did_receive_sync_update(&self);
 }
 
+/// Callbacks for the SAS (emoji/decimal) device verification flow.
+pub trait VerificationDelegate: Sync + Send {
+    fn verification_requested(&self);
+    fn sas_ready(&self, emoji: Vec<String>, decimals: Vec<u16>);
+    fn verification_done(&self);
+    fn verification_cancelled(&self);
+}
+
 impl Client {
 
     fn new(client: MatrixClient, state: ClientState) -> Self {
         Client {
             client,
             state: Arc::new(RwLock::new(state)),
+            verification_delegate: Arc::new(RwLock::new(None)),
+            sas: Arc::new(RwLock::new(None)),
         }
     }
 
     pub fn start_sync(&self, delegate: Box<dyn ClientDelegate>) {
         let client = self.client.clone();
         let state = self.state.clone();
+        let verification_delegate = self.verification_delegate.clone();
+        let sas_slot = self.sas.clone();
         RUNTIME.spawn(async move {
-            client.sync_with_callback(matrix_sdk::config::SyncSettings::new(), |_response| async {
+            client.sync_with_callback(matrix_sdk::config::SyncSettings::new(), |response| async {
+                for event in &response.to_device.events {
+                    handle_verification_event(&client, &verification_delegate, &sas_slot, event).await;
+                }
 
                 delegate.did_receive_sync_update();
 
@@ -209,6 +381,42 @@ impl Client {
         });
     }
 
+    pub fn set_verification_delegate(&self, delegate: Option<Box<dyn VerificationDelegate>>) {
+        *self.verification_delegate.write() = delegate.map(Arc::from);
+    }
+
+    /// Start a SAS verification of the device `device_id` of user `user_id`.
+    pub fn request_verification(&self, user_id: String, device_id: String) -> Result<()> {
+        let client = self.client.clone();
+        let sas_slot = self.sas.clone();
+        RUNTIME.block_on(async move {
+            let user_id = Box::<UserId>::try_from(user_id)?;
+            let device_id: Box<DeviceId> = device_id.into();
+            let device = client
+                .get_device(&user_id, &device_id)
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("Unknown device {device_id}"))?;
+            let sas = device.start_verification().await?;
+            *sas_slot.write() = Some(sas);
+            Ok(())
+        })
+    }
+
+    pub fn accept_verification(&self) -> Result<()> {
+        let sas = self.sas.read().clone().ok_or_else(|| anyhow::anyhow!("No verification in progress"))?;
+        RUNTIME.block_on(async move { Ok(sas.accept().await?) })
+    }
+
+    pub fn confirm_verification(&self) -> Result<()> {
+        let sas = self.sas.read().clone().ok_or_else(|| anyhow::anyhow!("No verification in progress"))?;
+        RUNTIME.block_on(async move { Ok(sas.confirm().await?) })
+    }
+
+    pub fn cancel_verification(&self) -> Result<()> {
+        let sas = self.sas.read().clone().ok_or_else(|| anyhow::anyhow!("No verification in progress"))?;
+        RUNTIME.block_on(async move { Ok(sas.cancel().await?) })
+    }
+
     /// Indication whether we've received a first sync response since
     /// establishing the client (in memory)
     pub fn has_first_synced(&self) -> bool {
@@ -228,24 +436,45 @@ impl Client {
     pub fn restore_token(&self) -> Result<String> {
         RUNTIME.block_on(async move {
             let session = self.client.session().await.expect("Missing session");
-            let homeurl = self.client.homeserver().await.into();
+            let homeserver = self.client.homeserver().await.into();
             Ok(serde_json::to_string(&RestoreToken {
-                session, homeurl, is_guest: self.state.read().is_guest,
+                session: FfiSession { homeserver, session },
+                is_guest: self.state.read().is_guest,
             })?)
         })
     }
 
+    /// The homeserver this client is logged in to.
+    pub fn homeserver_url(&self) -> String {
+        RUNTIME.block_on(async move { self.client.homeserver().await.into() })
+    }
+
     pub  fn conversations(&self) -> Vec<Arc<Room>> {
         self.rooms().into_iter().map(|room| Arc::new(Room { room })).collect()
     }
 
-    // pub fn get_mxcuri_media(&self, uri: String) -> Result<Vec<u8>> {
-    //     let l = self.client.clone();
-    //     RUNTIME.block_on(async move {
-    //         let user_id = l.user_id().await.expect("No User ID found");
-    //         Ok(user_id.as_str().to_string())
-    //     }).await?
-    // }
+    /// Resolve an `mxc://` URI (as seen on `MessageBody::Image`/`File` bodies)
+    /// to its bytes, optionally asking the homeserver for a thumbnail
+    /// instead of the original.
+    pub fn get_media_content(&self, mxc_uri: String, generate_thumbnail: bool) -> Result<Vec<u8>> {
+        let l = self.client.clone();
+        RUNTIME.block_on(async move {
+            let uri = Box::<MxcUri>::try_from(mxc_uri.as_str())?;
+            let format = if generate_thumbnail {
+                MediaFormat::Thumbnail(MediaThumbnailSize {
+                    method: Method::Scale,
+                    width: UInt::new(800).expect("800 fits in UInt"),
+                    height: UInt::new(800).expect("800 fits in UInt"),
+                })
+            } else {
+                MediaFormat::File
+            };
+            Ok(l.get_media_content(&MediaRequest {
+                media_type: MediaType::Uri(uri),
+                format,
+            }, true).await?)
+        })
+    }
 
     pub fn user_id(&self) -> Result<String> {
         let l = self.client.clone();
@@ -283,11 +512,12 @@ impl Client {
     }
 }
 
-pub fn guest_client(base_path: String, homeurl: String) -> Result<Arc<Client>> {
+pub fn guest_client(base_path: String, homeurl: String, device_name: Option<String>) -> Result<Arc<Client>> {
     let homeserver = Url::parse(&homeurl)?;
     let config = new_client_config(base_path, homeurl)?;
     let mut guest_registration = register::Request::new();
     guest_registration.kind = register::RegistrationKind::Guest;
+    guest_registration.initial_device_display_name = device_name;
     RUNTIME.block_on(async move {
         let client = MatrixClient::new_with_config(homeserver, config).await?;
         let register = client.register(guest_registration).await?;
@@ -302,9 +532,84 @@ pub fn guest_client(base_path: String, homeurl: String) -> Result<Arc<Client>> {
     })
 }
 
-pub fn login_with_token(base_path: String, restore_token: String) -> Result<Arc<Client>> {
-    let RestoreToken { session, homeurl, is_guest } = serde_json::from_str(&restore_token)?;
-    let homeserver = Url::parse(&homeurl)?;
+/// Register a new account, driving the User-Interactive Auth flow to
+/// completion when the homeserver requires it.
+///
+/// Homeservers that don't permit single-shot registration reply to the
+/// initial `register` call with a 401 describing the available auth
+/// flows. We only know how to complete the `m.login.dummy` stage
+/// ourselves; anything else (recaptcha, email verification, ...) is
+/// handed back to the caller via `ClientError::UnsupportedAuthStage` so
+/// the UI can decide what to do.
+pub fn register_client(
+    base_path: String,
+    homeurl: String,
+    username: String,
+    password: String,
+    device_name: Option<String>,
+) -> Result<Arc<Client>, ClientError> {
+    let homeserver = Url::parse(&homeurl).map_err(anyhow::Error::from)?;
+    let config = new_client_config(base_path, homeurl)?;
+
+    let make_request = |auth: Option<AuthData>| {
+        let mut request = register::Request::new();
+        request.username = Some(username.clone());
+        request.password = Some(password.clone());
+        request.initial_device_display_name = device_name.clone();
+        request.inhibit_login = false;
+        request.auth = auth;
+        request
+    };
+
+    RUNTIME.block_on(async move {
+        let client = MatrixClient::new_with_config(homeserver, config).await?;
+
+        let register = match client.register(make_request(None)).await {
+            Ok(response) => response,
+            Err(error) => match error.uiaa_response() {
+                Some(info) => {
+                    let session = info.session.clone().unwrap_or_default();
+
+                    // Only auto-complete a flow that `m.login.dummy` can
+                    // satisfy on its own; a flow like
+                    // `["m.login.recaptcha", "m.login.dummy"]` still needs
+                    // the earlier stage from the caller, so don't treat
+                    // dummy showing up anywhere as "done".
+                    let dummy_only_flow = info
+                        .flows
+                        .iter()
+                        .any(|flow| flow.stages.len() == 1 && flow.stages[0] == "m.login.dummy");
+
+                    if dummy_only_flow {
+                        let auth = AuthData::Dummy(Dummy { session: Some(session) });
+                        client.register(make_request(Some(auth))).await?
+                    } else {
+                        let stages = info.flows.iter().flat_map(|flow| flow.stages.clone()).collect();
+                        return Err(ClientError::UnsupportedAuthStage { stages, session });
+                    }
+                }
+                None => return Err(error.into()),
+            },
+        };
+
+        let session = Session {
+            access_token: register.access_token.expect("no access token given"),
+            user_id: register.user_id,
+            device_id: register.device_id.expect("device id is given by server"),
+        };
+        client.restore_login(session).await?;
+        let c = Client::new(client, ClientStateBuilder::default().is_guest(false).build().map_err(anyhow::Error::from)?);
+        Ok(Arc::new(c))
+    })
+}
+
+/// `device_name` is accepted for signature symmetry with the other
+/// constructors but otherwise ignored: restoring a token logs back in to
+/// the device the token was originally issued for, so there is no new
+/// device to name.
+pub fn login_with_token(base_path: String, restore_token: String, _device_name: Option<String>) -> Result<Arc<Client>> {
+    let RestoreToken { session: FfiSession { homeserver, session }, is_guest } = serde_json::from_str(&restore_token)?;
+    let homeserver = Url::parse(&homeserver)?;
     let config = new_client_config(base_path, session.user_id.to_string())?;
     // First we need to log in.
     RUNTIME.block_on(async move {
@@ -316,13 +621,13 @@ pub fn login_with_token(base_path: String, restore_token: String) -> Result<Arc<
 }
 
 
-pub fn login_new_client(base_path: String, username: String, password: String) -> Result<Arc<Client>> {
+pub fn login_new_client(base_path: String, username: String, password: String, device_name: Option<String>) -> Result<Arc<Client>> {
     let config = new_client_config(base_path, username.clone())?;
     let user = Box::<UserId>::try_from(username)?;
     // First we need to log in.
     RUNTIME.block_on(async move {
         let client = MatrixClient::new_from_user_id_with_config(&user, config).await?;
-        client.login(user, &password, None, None).await?;
+        client.login(user, &password, None, device_name.as_deref()).await?;
         let c = Client::new(client, ClientStateBuilder::default().is_guest(false).build()?);
         Ok(Arc::new(c))
     })
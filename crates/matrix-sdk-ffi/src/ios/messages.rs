@@ -0,0 +1,82 @@
+use std::sync::Arc;
+
+use matrix_sdk::ruma::{
+    events::{
+        room::message::{MessageType, RoomMessageEventContent},
+        AnySyncMessageEvent, AnySyncRoomEvent,
+    },
+    MilliSecondsSinceUnixEpoch,
+};
+use matrix_sdk::media::MediaSource;
+
+/// The typed content of a single timeline message, replacing the old
+/// `Debug`-formatted strings with something a UI can actually render.
+#[derive(Clone, Debug)]
+pub enum MessageBody {
+    Text { body: String, formatted_html: Option<String> },
+    Image { body: String, mxc_uri: String, thumbnail_mxc: Option<String> },
+    File { body: String, mxc_uri: String },
+    Notice { body: String, formatted_html: Option<String> },
+    Emote { body: String, formatted_html: Option<String> },
+}
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    pub sender: String,
+    pub origin_server_ts: u64,
+    pub event_id: String,
+    pub body: MessageBody,
+}
+
+fn mxc_from_source(source: &MediaSource) -> String {
+    match source {
+        MediaSource::Plain(uri) => uri.to_string(),
+        MediaSource::Encrypted(file) => file.url.to_string(),
+    }
+}
+
+/// Turn a `m.room.message` event into our typed `Message`, or `None` for
+/// message types we don't have a UI representation for yet.
+pub(crate) fn message_from_content(
+    sender: String,
+    event_id: String,
+    origin_server_ts: MilliSecondsSinceUnixEpoch,
+    content: RoomMessageEventContent,
+) -> Option<Arc<Message>> {
+    let body = match content.msgtype {
+        MessageType::Text(text) => MessageBody::Text {
+            body: text.body,
+            formatted_html: text.formatted.map(|f| f.body),
+        },
+        MessageType::Notice(notice) => MessageBody::Notice {
+            body: notice.body,
+            formatted_html: notice.formatted.map(|f| f.body),
+        },
+        MessageType::Emote(emote) => MessageBody::Emote {
+            body: emote.body,
+            formatted_html: emote.formatted.map(|f| f.body),
+        },
+        MessageType::Image(image) => MessageBody::Image {
+            body: image.body,
+            mxc_uri: mxc_from_source(&image.source),
+            thumbnail_mxc: image.info.and_then(|info| info.thumbnail_source).as_ref().map(mxc_from_source),
+        },
+        MessageType::File(file) => MessageBody::File {
+            body: file.body,
+            mxc_uri: mxc_from_source(&file.source),
+        },
+        _ => return None,
+    };
+
+    Some(Arc::new(Message { sender, origin_server_ts: origin_server_ts.0.into(), event_id, body }))
+}
+
+/// Adapt a live-timeline sync event into our typed `Message`.
+pub fn sync_event_to_message(event: AnySyncRoomEvent) -> Option<Arc<Message>> {
+    match event {
+        AnySyncRoomEvent::Message(AnySyncMessageEvent::RoomMessage(m)) => {
+            message_from_content(m.sender.to_string(), m.event_id.to_string(), m.origin_server_ts, m.content)
+        }
+        _ => None,
+    }
+}